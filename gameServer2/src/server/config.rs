@@ -0,0 +1,17 @@
+use std::path::PathBuf;
+
+pub struct ServerConfig {
+    pub record_demos: bool,
+    pub demos_dir: PathBuf,
+    pub max_demos: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            record_demos: false,
+            demos_dir: PathBuf::from("demos"),
+            max_demos: 100,
+        }
+    }
+}
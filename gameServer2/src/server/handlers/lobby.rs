@@ -3,6 +3,7 @@ use mio;
 use server::{
     server::HWServer,
     client::ClientId,
+    room::HWRoom,
     actions::{Action, Action::*}
 };
 use protocol::messages::{
@@ -43,14 +44,34 @@ pub fn handle(server: &mut HWServer, client_id: ClientId, message: HWProtocolMes
                     .filter(|(_, c)| c.room_id == room_id)
                     .map(|(_, c)| c.nick.clone())
                     .collect();
-                let c = &mut server.clients[client_id];
+                let c = &server.clients[client_id];
 
                 actions = if let Some((_, r)) = room {
                     if c.protocol_number != r.protocol_number {
                         vec![Warn("Room version incompatible to your Hedgewars version!".to_string())]
                     } else {
-                        vec![MoveToRoom(r.id),
-                             RoomJoined(nicks).send_self().action()]
+                        let mut msgs = vec![MoveToRoom(r.id),
+                             RoomJoined(nicks).send_self().action()];
+                        if let Some(ref info) = r.game_info {
+                            for (owner_id, team) in &r.teams {
+                                let owner = &server.clients[*owner_id];
+                                msgs.push(TeamAdd(HWRoom::team_info(owner, team))
+                                    .send_self().action());
+                                msgs.push(TeamColor(team.name.clone(), team.color)
+                                    .send_self().action());
+                                msgs.push(HedgehogsNumber(team.name.clone(), team.hedgehogs_number)
+                                    .send_self().action());
+                            }
+                            msgs.push(info.config.clone().into_server_msg()
+                                .send_self().action());
+
+                            let replay = info.msg_log.clone();
+                            if !replay.is_empty() {
+                                msgs.push(ForwardEngineMessage(replay)
+                                    .send_self().action());
+                            }
+                        }
+                        msgs
                     }
                 } else {
                     vec![Warn("No such room.".to_string())]
@@ -0,0 +1,3 @@
+mod common;
+pub mod inroom;
+pub mod lobby;
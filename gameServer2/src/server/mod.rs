@@ -0,0 +1,6 @@
+pub mod actions;
+pub mod client;
+pub mod config;
+pub mod handlers;
+pub mod room;
+pub mod server;
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use coretypes::{GameCfg, VoteType};
+use server::client::{ClientId, HWClient};
+
+pub type RoomId = usize;
+pub type TeamColor = u32;
+
+pub struct HWTeam {
+    pub name: String,
+    pub color: TeamColor,
+    pub hedgehogs_number: u8,
+}
+
+pub struct GameInfo {
+    pub config: GameCfg,
+    pub msg_log: Vec<String>,
+    pub last_msg: Option<String>,
+}
+
+pub struct ActiveVote {
+    pub vote_type: VoteType,
+    pub votes: HashMap<ClientId, bool>,
+    pub deadline: Instant,
+}
+
+pub struct HWRoom {
+    pub id: RoomId,
+    pub name: String,
+    pub protocol_number: u32,
+    pub team_limit: u8,
+    pub players_number: u8,
+    pub ready_players_number: u32,
+    pub teams_in_game: u8,
+    pub teams: Vec<(ClientId, HWTeam)>,
+    pub game_info: Option<GameInfo>,
+    pub active_vote: Option<ActiveVote>,
+    pub is_paused: bool,
+}
+
+impl HWRoom {
+    // Centralizes the defaults for fields a brand new room doesn't have yet
+    // (no vote in progress, not paused) so that whatever builds a room on
+    // CreateRoom only has to supply what's actually choosable at creation time.
+    pub fn new(id: RoomId, name: String, protocol_number: u32, team_limit: u8) -> HWRoom {
+        HWRoom {
+            id,
+            name,
+            protocol_number,
+            team_limit,
+            players_number: 0,
+            ready_players_number: 0,
+            teams_in_game: 0,
+            teams: Vec::new(),
+            game_info: None,
+            active_vote: None,
+            is_paused: false,
+        }
+    }
+
+    pub fn team_info(owner: &HWClient, team: &HWTeam) -> ::protocol::messages::TeamInfo {
+        ::protocol::messages::TeamInfo {
+            owner: owner.nick.clone(),
+            name: team.name.clone(),
+            color: team.color,
+            hedgehogs_number: team.hedgehogs_number,
+        }
+    }
+
+    pub fn addable_hedgehogs(&self) -> u8 {
+        let used: u8 = self.teams.iter().map(|(_, t)| t.hedgehogs_number).sum();
+        48u8.saturating_sub(used)
+    }
+
+    pub fn find_team<F>(&self, f: F) -> Option<&HWTeam>
+        where F: Fn(&HWTeam) -> bool {
+        self.teams.iter().map(|(_, t)| t).find(|t| f(t))
+    }
+
+    pub fn add_team(&mut self, owner: ClientId, info: HWTeam) -> &HWTeam {
+        self.teams.push((owner, info));
+        &self.teams.last().unwrap().1
+    }
+
+    pub fn find_team_owner(&self, name: &str) -> Option<(ClientId, &str)> {
+        self.teams.iter()
+            .find(|(_, t)| t.name == name)
+            .map(|(id, t)| (*id, t.name.as_str()))
+    }
+
+    pub fn find_team_color(&self, owner: ClientId) -> Option<TeamColor> {
+        self.teams.iter().find(|(id, _)| *id == owner).map(|(_, t)| t.color)
+    }
+
+    pub fn find_team_and_owner_mut<F>(&mut self, f: F) -> Option<(ClientId, &mut HWTeam)>
+        where F: Fn(&HWTeam) -> bool {
+        self.teams.iter_mut()
+            .find(|(_, t)| f(t))
+            .map(|(id, t)| (*id, t))
+    }
+
+    pub fn client_teams(&self, owner: ClientId) -> impl Iterator<Item = &HWTeam> {
+        self.teams.iter().filter(move |(id, _)| *id == owner).map(|(_, t)| t)
+    }
+
+    pub fn set_config(&mut self, cfg: GameCfg) {
+        if let Some(ref mut info) = self.game_info {
+            info.config = cfg;
+        }
+    }
+}